@@ -1,19 +1,119 @@
 
+extern crate pkg_config;
+
 use std::collections::HashMap;
 use std::env;
 use std::fs::File;
 use std::io::prelude::*;
 use std::io;
+use std::path::PathBuf;
 
-fn load_cfg() -> io::Result<HashMap<String,String>> {
-    let mut path = env::var("OUT_DIR").unwrap().to_owned();
-    path.push_str("/../../../../../../../config.cargo");
+// The maximum number of directories to check for config.cargo, starting
+// with OUT_DIR itself and walking upward, before giving up.
+const MAX_CONFIG_CARGO_SEARCH_DEPTH : usize = 16;
 
-    let f = File::open(&path)?;
-    let reader = io::BufReader::new(f);
-    let mut map = HashMap::new();
-    for line in reader.lines() {
-        let s = line?;
+// Prefix of a linker rpath argument, as passed through by the C compiler
+// driver, e.g. "-Wl,-rpath,/usr/lib/foo".
+const RPATH_PREFIX : &str = "-Wl,-rpath,";
+
+// The search paths and static components to link, used when config.cargo
+// doesn't declare its own [search-paths]/[static-components] (i.e., the
+// legacy flat format).
+const DEFAULT_SEARCH_PATHS : &[&str] = &[
+    "src/common",
+    "src/or",
+    "src/ext/keccak-tiny",
+    "src/ext/keccak-tiny",
+    "src/ext/ed25519/ref10",
+    "src/ext/ed25519/donna",
+    "src/trunnel",
+    "src/trace",
+];
+
+const DEFAULT_STATIC_COMPONENTS : &[&str] = &[
+    "tor-testing",
+    "or-crypto-testing",
+    "or-ctime-testing",
+    "or-testing",
+    "or-ctime-testing",
+    "or-event-testing",
+    "or-trunnel-testing",
+    "or-trace",
+    "curve25519_donna",
+    "keccak-tiny",
+    "ed25519_ref10",
+    "ed25519_donna",
+];
+
+// Find the path to config.cargo, either from $TOR_CONFIG_CARGO, or by
+// walking upward from OUT_DIR until we find it (or give up).
+//
+// We have to search upward like this -- rather than using a single
+// fixed relative path -- because the depth of OUT_DIR beneath the
+// top-level build directory isn't something we can rely on; it has
+// changed in the past as Cargo's target-directory layout changed.
+fn find_config_cargo() -> io::Result<String> {
+    if let Ok(path) = env::var("TOR_CONFIG_CARGO") {
+        return Ok(path);
+    }
+
+    let out_dir = env::var("OUT_DIR").unwrap();
+    let mut dir = PathBuf::from(&out_dir);
+    let mut searched = Vec::new();
+
+    for _ in 0..MAX_CONFIG_CARGO_SEARCH_DEPTH {
+        let candidate = dir.join("config.cargo");
+        if candidate.is_file() {
+            return Ok(candidate.to_str().unwrap().to_owned());
+        }
+        searched.push(candidate.display().to_string());
+        if !dir.pop() {
+            break;
+        }
+    }
+
+    Err(io::Error::new(io::ErrorKind::NotFound, format!(
+        "Could not find config.cargo anywhere above OUT_DIR={}. Searched:\n  {}\n\
+         Set TOR_CONFIG_CARGO to the path of config.cargo to override this search.",
+        out_dir, searched.join("\n  "))))
+}
+
+// The parsed contents of config.cargo: a flat map of KEY=VALUE settings
+// (as produced by the legacy format, or any line containing "=" in the
+// structured format, in whatever section), plus the declarative lists
+// used by the structured [static-components]/[dynamic-deps]/
+// [search-paths] format. In the legacy format, the lists are always
+// empty, and main() falls back to its built-in component list.
+struct Config {
+    vars : HashMap<String, String>,
+    static_components : Vec<String>,
+    dynamic_deps : Vec<String>,
+    search_paths : Vec<String>,
+}
+
+impl Config {
+    fn new() -> Config {
+        Config {
+            vars: HashMap::new(),
+            static_components: Vec::new(),
+            dynamic_deps: Vec::new(),
+            search_paths: Vec::new(),
+        }
+    }
+    fn get(&self, key : &str) -> Option<&String> {
+        self.vars.get(key)
+    }
+}
+
+// True if "line" looks like the start of a [section] header.
+fn is_section_header(line : &str) -> bool {
+    line.starts_with('[') && line.ends_with(']')
+}
+
+// Parse the legacy flat KEY=VALUE format.
+fn parse_flat_cfg(lines : &[String]) -> Config {
+    let mut cfg = Config::new();
+    for s in lines {
         if s.starts_with("#") {
             continue;
         }
@@ -23,9 +123,67 @@ fn load_cfg() -> io::Result<HashMap<String,String>> {
         };
         let (var,eq_val) = s.split_at(idx);
         let val = &eq_val[1..];
-        map.insert(var.to_owned(), val.to_owned());
+        cfg.vars.insert(var.to_owned(), val.to_owned());
     }
-    Ok(map)
+    cfg
+}
+
+// Parse the structured [section]-based format: [static-components],
+// [dynamic-deps], and [search-paths] are declarative lists (one entry
+// per line); any other line -- whether before the first section header,
+// inside an unrecognized section, or following a list section -- is a
+// KEY=VALUE var whenever it contains "=". This lets file-level settings
+// appear after a list section without being swallowed by it.
+fn parse_structured_cfg(lines : &[String]) -> Config {
+    let mut cfg = Config::new();
+    let mut section = String::new();
+    for raw in lines {
+        let s = raw.trim();
+        if s.is_empty() || s.starts_with("#") {
+            continue;
+        }
+        if is_section_header(s) {
+            section = s[1..s.len() - 1].trim().to_lowercase();
+            continue;
+        }
+        if let Some(idx) = s.find("=") {
+            let (var, eq_val) = s.split_at(idx);
+            cfg.vars.insert(var.trim().to_owned(), eq_val[1..].trim().to_owned());
+            continue;
+        }
+        match section.as_str() {
+            "static-components" => cfg.static_components.push(s.to_owned()),
+            "dynamic-deps" => cfg.dynamic_deps.push(s.to_owned()),
+            "search-paths" => cfg.search_paths.push(s.to_owned()),
+            _ => {}
+        }
+    }
+    cfg
+}
+
+fn load_cfg() -> io::Result<(String, Config)> {
+    let path = find_config_cargo()?;
+
+    let f = File::open(&path)?;
+    let reader = io::BufReader::new(f);
+    let mut lines = Vec::new();
+    for line in reader.lines() {
+        lines.push(line?);
+    }
+
+    // A structured manifest either has a ".toml" extension, or starts
+    // (ignoring blank lines and comments) with a "[section]" header.
+    let is_structured = path.ends_with(".toml") || lines.iter()
+        .map(|l| l.trim())
+        .find(|l| !l.is_empty() && !l.starts_with("#"))
+        .is_some_and(is_section_header);
+
+    let cfg = if is_structured {
+        parse_structured_cfg(&lines)
+    } else {
+        parse_flat_cfg(&lines)
+    };
+    Ok((path, cfg))
 }
 
 fn component(s : &str) {
@@ -38,15 +196,88 @@ fn dependency(s : &str) {
 
 fn link_relpath(builddir : &str, s : &str) {
     println!("cargo:rustc-link-search=native={}/{}", builddir, s);
+    println!("cargo:rerun-if-changed={}/{}", builddir, s);
 }
 
 fn link_path(s : &str) {
     println!("cargo:rustc-link-search=native={}", s);
 }
 
+fn framework(s : &str) {
+    println!("cargo:rustc-link-lib=framework={}", s);
+}
+
+// Cargo only passes rustc-link-arg through to bin/example/test/bench
+// targets that it links itself -- never to this crate's own lib build,
+// which is the library the outer autotools build consumes. So this
+// can't embed an rpath in the final `tor` executable; it only reaches
+// targets Cargo links directly, such as `cargo test` binaries.
+//
+// Embedding an rpath in the actual `tor` binary -- the cross-linking
+// case this was meant for -- is NOT handled here: that link step is
+// done by the outer autotools build, which never sees this directive.
+// Doing that needs the autotools/Makefile.am side to consume the rpath
+// (e.g. read it back out of config.cargo or a file this script writes),
+// which is out of scope for this build.rs change.
+fn rpath(s : &str) {
+    println!("cargo:rustc-link-arg=-Wl,-rpath,{}", s);
+}
+
+// The value of CARGO_CFG_TARGET_OS, e.g. "windows", "macos", "linux".
+fn target_os() -> String {
+    env::var("CARGO_CFG_TARGET_OS").unwrap_or_default()
+}
+
+// True if we're building for a Windows target.
+fn target_is_windows() -> bool {
+    target_os() == "windows"
+}
+
+// If TOR_USE_PKG_CONFIG is set in the environment, prefer pkg-config over
+// whatever config.cargo says, even when config.cargo has the answer.
+fn use_pkg_config() -> bool {
+    env::var("TOR_USE_PKG_CONFIG").is_ok()
+}
+
+// Ask pkg-config for "name", and emit the same rustc-link-search /
+// rustc-link-lib directives that from_c() would have produced from
+// config.cargo.
+fn pkg_config_probe(name : &str) {
+    match pkg_config::Config::new().probe(name) {
+        Ok(lib) => {
+            for p in &lib.link_paths {
+                link_path(p.to_str().unwrap());
+            }
+            for l in &lib.libs {
+                dependency(l);
+            }
+        }
+        Err(e) => panic!("pkg-config could not find {}: {}", name, e)
+    }
+}
+
+// Link against "pkg_name", using the flags in cfg[key] unless pkg-config
+// has been requested or config.cargo didn't have that key.
+fn link_lib_flags(cfg : &Config, key : &str, pkg_name : &str) {
+    match cfg.get(key) {
+        Some(flags) if !use_pkg_config() => from_c(flags),
+        _ => pkg_config_probe(pkg_name)
+    }
+}
+
+// Emit from_c(cfg[key]) only if config.cargo defined that key; this is a
+// no-op when config.cargo could not be loaded in pkg-config mode, where
+// cfg is empty.
+fn optional_flags(cfg : &Config, key : &str) {
+    if let Some(flags) = cfg.get(key) {
+        from_c(flags);
+    }
+}
+
 fn from_c(s : &str) {
     let mut next_is_lib = false;
     let mut next_is_path = false;
+    let mut next_is_framework = false;
     for ent in s.split_whitespace() {
         if next_is_lib {
             dependency(ent);
@@ -54,60 +285,112 @@ fn from_c(s : &str) {
         } else if next_is_path {
             link_path(ent);
             next_is_path = false;
+        } else if next_is_framework {
+            framework(ent);
+            next_is_framework = false;
         } else if ent == "-l" {
             next_is_lib = true;
         } else if ent == "-L" {
             next_is_path = true;
+        } else if ent == "-framework" {
+            next_is_framework = true;
         } else if ent.starts_with("-L") {
             link_path(&ent[2..]);
         } else if ent.starts_with("-l") {
             dependency(&ent[2..]);
+        } else if let Some(dir) = ent.strip_prefix(RPATH_PREFIX) {
+            rpath(dir);
         }
     }
 }
 
 pub fn main() {
-    let cfg = load_cfg().unwrap();
-
-    let builddir = cfg.get("BUILDDIR").unwrap();
-
-    from_c(cfg.get("TOR_LDFLAGS_zlib").unwrap());
-    from_c(cfg.get("TOR_LDFLAGS_openssl").unwrap());
-    from_c(cfg.get("TOR_LDFLAGS_libevent").unwrap());
-
-    link_relpath(builddir, "src/common");
-    link_relpath(builddir, "src/or");
-    link_relpath(builddir, "src/ext/keccak-tiny");
-    link_relpath(builddir, "src/ext/keccak-tiny");
-    link_relpath(builddir, "src/ext/ed25519/ref10");
-    link_relpath(builddir, "src/ext/ed25519/donna");
-    link_relpath(builddir, "src/trunnel");
-    link_relpath(builddir, "src/trace");
-
-    component("tor-testing");
-    component("or-crypto-testing");
-    component("or-ctime-testing");
-    component("or-testing");
-    component("or-ctime-testing");
-    component("or-event-testing");
-    component("or-trunnel-testing");
-    component("or-trace");
-    component("curve25519_donna");
-    component("keccak-tiny");
-    component("ed25519_ref10");
-    component("ed25519_donna");
-
-    from_c(cfg.get("TOR_ZLIB_LIBS").unwrap());
-    from_c(cfg.get("TOR_LIB_MATH").unwrap());
-    from_c(cfg.get("TOR_LIBEVENT_LIBS").unwrap());
-    from_c(cfg.get("TOR_OPENSSL_LIBS").unwrap());
-    from_c(cfg.get("TOR_LIB_WS32").unwrap());
-    from_c(cfg.get("TOR_LIB_GDI").unwrap());
-    from_c(cfg.get("TOR_LIB_USERENV").unwrap());
-    from_c(cfg.get("CURVE25519_LIBS").unwrap());
-    from_c(cfg.get("TOR_SYSTEMD_LIBS").unwrap());
-    from_c(cfg.get("TOR_LZMA_LIBS").unwrap());
-    from_c(cfg.get("TOR_ZSTD_LIBS").unwrap());
-    from_c(cfg.get("LIBS").unwrap())
+    let cfg = match load_cfg() {
+        Ok((cfg_path, cfg)) => {
+            println!("cargo:rerun-if-changed={}", cfg_path);
+            cfg
+        }
+        Err(e) => {
+            if use_pkg_config() {
+                Config::new()
+            } else {
+                panic!("Could not load config.cargo: {}", e);
+            }
+        }
+    };
+    println!("cargo:rerun-if-env-changed=OUT_DIR");
+    println!("cargo:rerun-if-env-changed=TOR_CONFIG_CARGO");
+    println!("cargo:rerun-if-env-changed=TOR_USE_PKG_CONFIG");
+
+    link_lib_flags(&cfg, "TOR_LDFLAGS_zlib", "zlib");
+    link_lib_flags(&cfg, "TOR_LDFLAGS_openssl", "openssl");
+    link_lib_flags(&cfg, "TOR_LDFLAGS_libevent", "libevent");
 
+    if let Some(builddir) = cfg.get("BUILDDIR") {
+        if cfg.search_paths.is_empty() {
+            for p in DEFAULT_SEARCH_PATHS.iter() {
+                link_relpath(builddir, p);
+            }
+        } else {
+            for p in &cfg.search_paths {
+                link_relpath(builddir, p);
+            }
+        }
+
+        if cfg.static_components.is_empty() {
+            for c in DEFAULT_STATIC_COMPONENTS.iter() {
+                component(c);
+            }
+        } else {
+            for c in &cfg.static_components {
+                component(c);
+            }
+        }
+    }
+
+    for d in &cfg.dynamic_deps {
+        dependency(d);
+    }
+
+    optional_flags(&cfg, "TOR_ZLIB_LIBS");
+    optional_flags(&cfg, "TOR_LIB_MATH");
+    optional_flags(&cfg, "TOR_LIBEVENT_LIBS");
+    optional_flags(&cfg, "TOR_OPENSSL_LIBS");
+    if target_is_windows() {
+        optional_flags(&cfg, "TOR_LIB_WS32");
+        optional_flags(&cfg, "TOR_LIB_GDI");
+        optional_flags(&cfg, "TOR_LIB_USERENV");
+    }
+    optional_flags(&cfg, "CURVE25519_LIBS");
+    optional_flags(&cfg, "TOR_SYSTEMD_LIBS");
+    link_lib_flags(&cfg, "TOR_LZMA_LIBS", "liblzma");
+    link_lib_flags(&cfg, "TOR_ZSTD_LIBS", "libzstd");
+    optional_flags(&cfg, "LIBS");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(s : &str) -> Vec<String> {
+        s.lines().map(|l| l.to_owned()).collect()
+    }
+
+    #[test]
+    fn vars_after_a_list_section_are_not_swallowed() {
+        let cfg = parse_structured_cfg(&lines(
+            "[search-paths]\nsrc/custom1\n\nBUILDDIR=/tmp/builddir4\n"));
+
+        assert_eq!(cfg.get("BUILDDIR"), Some(&"/tmp/builddir4".to_owned()));
+        assert_eq!(cfg.search_paths, vec!["src/custom1".to_owned()]);
+    }
+
+    #[test]
+    fn list_sections_collect_their_entries() {
+        let cfg = parse_structured_cfg(&lines(
+            "[static-components]\nfoo\nbar\n[dynamic-deps]\nbaz\n"));
+
+        assert_eq!(cfg.static_components, vec!["foo".to_owned(), "bar".to_owned()]);
+        assert_eq!(cfg.dynamic_deps, vec!["baz".to_owned()]);
+    }
 }